@@ -0,0 +1,145 @@
+use embedded_sdmmc::{BlockDevice, RawDirectory, RawFile, TimeSource, VolumeManager};
+use embedded_svc::http::Method;
+use esp_idf_svc::{
+    http::client::{Configuration as HttpConfiguration, EspHttpConnection},
+    io::Read as EspIoRead,
+};
+
+/// A blocking byte source the playback pipeline can read PCM/WAV bytes from,
+/// without caring whether they come from the SD card or over the network.
+/// Implementations that can't seek (e.g. an HTTP stream) should return an
+/// error from `seek` rather than silently doing nothing.
+pub trait AudioSource: Send {
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize>;
+    fn seek(&mut self, offset: u32) -> anyhow::Result<()>;
+}
+
+/// An [`AudioSource`] backed by a file already open on the SD card via
+/// `embedded_sdmmc`.
+pub struct SdCardSource<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+> where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    volume_mgr: &'a mut VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: RawFile,
+}
+
+impl<'a, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    SdCardSource<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    pub fn new(
+        volume_mgr: &'a mut VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+        file: RawFile,
+    ) -> Self {
+        Self { volume_mgr, file }
+    }
+}
+
+impl<'a, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize> AudioSource
+    for SdCardSource<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice + Send,
+    T: TimeSource + Send,
+{
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        self.volume_mgr
+            .read(self.file, buf)
+            .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))
+    }
+
+    fn seek(&mut self, offset: u32) -> anyhow::Result<()> {
+        self.volume_mgr
+            .file_seek_from_start(self.file, offset)
+            .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))
+    }
+}
+
+// A known foot-gun with `embedded_sdmmc`: reusing or reopening a file handle without closing it
+// first trips its lock assertions, so always close the handle once this source is done with it.
+impl<'a, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize> Drop
+    for SdCardSource<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn drop(&mut self) {
+        let _ = self.volume_mgr.close_file(self.file);
+    }
+}
+
+/// An [`AudioSource`] that streams PCM/WAV bytes from an HTTP(S) URL over
+/// Wi-Fi, one response body read at a time. Does not support seeking: by the
+/// time playback needs to seek, the body has already started streaming.
+pub struct HttpSource {
+    connection: EspHttpConnection,
+}
+
+impl HttpSource {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let mut connection = EspHttpConnection::new(&HttpConfiguration {
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })
+        .map_err(|e| anyhow::anyhow!("HTTP error: {:?}", e))?;
+
+        connection
+            .initiate_request(Method::Get, url, &[])
+            .map_err(|e| anyhow::anyhow!("HTTP error: {:?}", e))?;
+        connection
+            .initiate_response()
+            .map_err(|e| anyhow::anyhow!("HTTP error: {:?}", e))?;
+
+        Ok(Self { connection })
+    }
+}
+
+impl AudioSource for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        EspIoRead::read(&mut self.connection, buf).map_err(|e| anyhow::anyhow!("HTTP error: {:?}", e))
+    }
+
+    fn seek(&mut self, _offset: u32) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("seeking is not supported for HTTP audio sources"))
+    }
+}
+
+/// Opens an [`AudioSource`] for `uri`, dispatching on its scheme: `file://<name>` opens `<name>`
+/// in `dir` on the SD card, `http://` / `https://` streams from the network. This is what lets
+/// the same header parser and I2S config logic drive either a local file or a remote stream.
+pub fn open_audio_source<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    uri: &str,
+    volume_mgr: &'a mut VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    dir: RawDirectory,
+) -> anyhow::Result<Box<dyn AudioSource + 'a>>
+where
+    D: BlockDevice + Send + 'a,
+    T: TimeSource + Send + 'a,
+{
+    if let Some(file_name) = uri.strip_prefix("file://") {
+        let file = volume_mgr
+            .open_file_in_dir(dir, file_name, embedded_sdmmc::Mode::ReadOnly)
+            .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+        Ok(Box::new(SdCardSource::new(volume_mgr, file)))
+    } else if uri.starts_with("http://") || uri.starts_with("https://") {
+        Ok(Box::new(HttpSource::new(uri)?))
+    } else {
+        Err(anyhow::anyhow!("unsupported audio source URI: {:?}", uri))
+    }
+}
@@ -0,0 +1,117 @@
+/// Applies a constant software gain to 16-bit little-endian PCM samples,
+/// ramping linearly from 0 up to the target gain over the first `fade_in_samples`
+/// individual samples (not frames) seen across calls to [`process`](Self::process).
+///
+/// This suppresses the audible "de"/pop caused by the abrupt silence-to-full-amplitude
+/// transition at the very start of I2S playback.
+///
+/// Only 16-bit PCM is supported: [`process`](Self::process) reinterprets every 2 bytes
+/// of the buffer as one `i16` sample, which would run over misaligned sample boundaries
+/// for 8/24/32-bit files. Construct with any other `bits_per_sample` and `process` is a
+/// no-op, leaving the buffer untouched.
+pub struct GainProcessor {
+    target_gain: f32,
+    fade_in_samples: u32,
+    samples_processed: u32,
+    enabled: bool,
+}
+
+impl GainProcessor {
+    /// `target_gain` is clamped to 0.0-1.0. `fade_in_samples` is the number of
+    /// individual (per-channel) samples the fade-in ramp spans; 0 disables the ramp.
+    /// `bits_per_sample` must be 16 for gain/fade-in to actually apply; see the struct docs.
+    pub fn new(target_gain: f32, fade_in_samples: u32, bits_per_sample: u16) -> Self {
+        Self {
+            target_gain: target_gain.clamp(0.0, 1.0),
+            fade_in_samples,
+            samples_processed: 0,
+            enabled: bits_per_sample == 16,
+        }
+    }
+
+    /// Scales every 16-bit sample in `buffer` in place by the current gain,
+    /// advancing the fade-in ramp as samples are consumed. No-op unless this
+    /// processor was constructed for 16-bit PCM.
+    pub fn process(&mut self, buffer: &mut [u8]) {
+        if !self.enabled {
+            return;
+        }
+
+        for sample_bytes in buffer.chunks_exact_mut(2) {
+            let sample = i16::from_le_bytes([sample_bytes[0], sample_bytes[1]]);
+            let gain = self.gain_for_next_sample();
+            let scaled = (sample as f32 * gain)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            sample_bytes.copy_from_slice(&scaled.to_le_bytes());
+        }
+    }
+
+    fn gain_for_next_sample(&mut self) -> f32 {
+        let gain = if self.samples_processed < self.fade_in_samples {
+            self.target_gain * (self.samples_processed as f32 / self.fade_in_samples as f32)
+        } else {
+            self.target_gain
+        };
+
+        self.samples_processed = self.samples_processed.saturating_add(1);
+        gain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples_to_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    fn bytes_to_samples(buffer: &[u8]) -> Vec<i16> {
+        buffer
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn applies_constant_gain_once_past_the_fade_in() {
+        let mut gain = GainProcessor::new(0.5, 0, 16);
+        let mut buffer = samples_to_bytes(&[1000, -1000, 20_000]);
+
+        gain.process(&mut buffer);
+
+        assert_eq!(bytes_to_samples(&buffer), vec![500, -500, 10_000]);
+    }
+
+    #[test]
+    fn ramps_linearly_over_fade_in_samples() {
+        let mut gain = GainProcessor::new(1.0, 4, 16);
+        let mut buffer = samples_to_bytes(&[1000, 1000, 1000, 1000, 1000]);
+
+        gain.process(&mut buffer);
+
+        assert_eq!(bytes_to_samples(&buffer), vec![0, 250, 500, 750, 1000]);
+    }
+
+    #[test]
+    fn clamps_instead_of_wrapping_at_the_i16_boundary() {
+        let mut gain = GainProcessor::new(1.0, 0, 16);
+        let mut buffer = samples_to_bytes(&[i16::MAX, i16::MIN]);
+
+        gain.process(&mut buffer);
+
+        assert_eq!(bytes_to_samples(&buffer), vec![i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn is_a_no_op_for_non_16_bit_pcm() {
+        let mut gain = GainProcessor::new(0.5, 0, 24);
+        let original = vec![1, 2, 3, 4, 5, 6];
+        let mut buffer = original.clone();
+
+        gain.process(&mut buffer);
+
+        assert_eq!(buffer, original);
+    }
+}
@@ -1,7 +1,7 @@
-use std::{convert::TryInto, time::Instant};
+use std::time::Instant;
 
 use {
-    embedded_sdmmc::{SdCard, TimeSource, Timestamp},
+    embedded_sdmmc::{BlockDevice, RawDirectory, SdCard, TimeSource, Timestamp},
     esp_idf_hal::{
         delay::{Ets, FreeRtos, TickType},
         gpio::*,
@@ -10,7 +10,7 @@ use {
                 Config, DataBitWidth, SlotMode, StdClkConfig, StdConfig, StdGpioConfig,
                 StdSlotConfig,
             },
-            I2sDriver, I2sTx,
+            I2sDriver, I2sRx, I2sTx, I2S0,
         },
         prelude::*,
         spi::{config::Duplex, Dma, SpiConfig, SpiDeviceDriver, SpiDriver, SpiDriverConfig},
@@ -19,6 +19,18 @@ use {
     log::*,
 };
 
+mod audio_source;
+mod gain;
+mod playback;
+mod record;
+mod wav;
+
+use audio_source::open_audio_source;
+use gain::GainProcessor;
+use playback::{play_prefetched, prime_silence};
+use record::{patch_header_sizes, record_to_file, write_placeholder_header};
+use wav::{parse_wav_header, AudioFormat, WavInfo};
+
 pub struct SdMmcClock;
 
 impl TimeSource for SdMmcClock {
@@ -34,13 +46,135 @@ impl TimeSource for SdMmcClock {
     }
 }
 
-// The filename needs to correspond to 8.3 naming, so it should be no more than 8 characters long, with the extension .wav at the end.
-const WAV_FILE: &str = "gettys_m.wav";
-//const FILE_TO_READ: &str = "laugh_m.wav";
-
 const BLOCK_TIME: TickType = TickType::new(100_000_000); // Long enough we should not expect to ever return.
-const SAMPLE_RATE_HZ: u32 = 44100;
-const BYTES_IN_HEADER: u8 = 44;
+
+// Note from the MAX98357A data sheet:
+// LRCLK ONLY supports 8kHz, 16kHz, 32kHz, 44.1kHz, 48kHz, 88.2kHz and 96kHz frequencies.
+// LRCLK clocks at 11.025kHz, 12kHz, 22.05kHz and 24kHz are NOT supported.
+const SUPPORTED_SAMPLE_RATES_HZ: [u32; 7] = [8_000, 16_000, 32_000, 44_100, 48_000, 88_200, 96_000];
+
+// auto_clear = false so that the dma buffers are a ring buffer; minimum dma buffer count is 2.
+const DMA_BUFFER_COUNT: u32 = 2;
+const FRAMES_PER_BUFFER: usize = 512;
+
+// Flip to record a fresh WAV file from the I2S microphone instead of playing the SD card's
+// playlist. Sample rate/channels/bit-width are shared with the playback path via AudioFormat, so
+// whatever gets recorded here also plays back correctly through the MAX98357A.
+const RECORD_MODE: bool = false;
+const RECORD_FILE: &str = "rec_m.wav";
+const RECORD_SECONDS: u32 = 5;
+const RECORD_FORMAT: AudioFormat = AudioFormat {
+    num_channels: 1,
+    sample_rate: 44_100,
+    bits_per_sample: 16,
+};
+
+// Software volume applied to every played-back sample, and the length of the linear fade-in
+// ramp at the start of each track, used to suppress the startup pop. Both apply to 16-bit PCM
+// tracks only; tracks with any other bit depth play back unscaled and without the ramp.
+const PLAYBACK_GAIN: f32 = 1.0;
+const FADE_IN_MS: u32 = 15;
+
+/// A `.wav` file found in the root directory, along with its already-parsed
+/// header, so the playlist loop does not need to re-open and re-parse a file
+/// just to know its format.
+struct WavEntry {
+    file_name: String,
+    info: WavInfo,
+}
+
+/// Rejects sample rates the MAX98357A can't clock out, so a file like a
+/// 22.05 kHz recording fails fast instead of playing back garbled.
+fn validate_sample_rate_hz(sample_rate_hz: u32) -> anyhow::Result<()> {
+    if SUPPORTED_SAMPLE_RATES_HZ.contains(&sample_rate_hz) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "unsupported sample rate {} Hz; MAX98357A only supports {:?}",
+            sample_rate_hz,
+            SUPPORTED_SAMPLE_RATES_HZ
+        ))
+    }
+}
+
+/// Maps WAV `bits_per_sample` to the I2S driver's bit width, rejecting
+/// anything the driver can't be configured for.
+fn to_data_bit_width(bits_per_sample: u16) -> anyhow::Result<DataBitWidth> {
+    match bits_per_sample {
+        8 => Ok(DataBitWidth::Bits8),
+        16 => Ok(DataBitWidth::Bits16),
+        24 => Ok(DataBitWidth::Bits24),
+        32 => Ok(DataBitWidth::Bits32),
+        other => Err(anyhow::anyhow!("unsupported bits per sample: {}", other)),
+    }
+}
+
+/// Builds the I2S std-mode config for a given audio format, validating the
+/// fields that feed the clock and slot layout along the way. Shared by both
+/// the playback path (format parsed from a file) and the recording path
+/// (format fixed by [`RECORD_FORMAT`]).
+fn build_i2s_config(format: &AudioFormat) -> anyhow::Result<StdConfig> {
+    validate_sample_rate_hz(format.sample_rate)?;
+    let data_bit_width = to_data_bit_width(format.bits_per_sample)?;
+    let slot_mode = if format.num_channels == 2 {
+        SlotMode::Stereo
+    } else {
+        SlotMode::Mono
+    };
+
+    Ok(StdConfig::new(
+        Config::default()
+            .auto_clear(false)
+            .dma_buffer_count(DMA_BUFFER_COUNT)
+            .frames_per_buffer(FRAMES_PER_BUFFER as u32),
+        StdClkConfig::from_sample_rate_hz(format.sample_rate),
+        StdSlotConfig::philips_slot_default(data_bit_width, slot_mode),
+        StdGpioConfig::default(),
+    ))
+}
+
+/// Records [`RECORD_SECONDS`] of [`RECORD_FORMAT`] audio from the I2S mic into
+/// [`RECORD_FILE`] in the root directory: a placeholder header is written
+/// first, then PCM frames are streamed in, and finally the header's size
+/// fields are patched with the real byte counts.
+fn record_new_wav_file<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>(
+    volume_mgr: &mut embedded_sdmmc::VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    root_dir: RawDirectory,
+    i2s_0: I2S0,
+    bclk: Gpio0,
+    din: Gpio16,
+    ws: Gpio18,
+) -> anyhow::Result<()>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let i2s_config = build_i2s_config(&RECORD_FORMAT)?;
+    let mut i2s = I2sDriver::<I2sRx>::new_std_rx(i2s_0, &i2s_config, bclk, din, AnyIOPin::none(), ws)?;
+
+    info!("========== Recording to {:?} ==========", RECORD_FILE);
+    let file = volume_mgr
+        .open_file_in_dir(root_dir, RECORD_FILE, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+
+    write_placeholder_header(volume_mgr, file, &RECORD_FORMAT)?;
+
+    let max_data_bytes = RECORD_FORMAT.byte_rate() * RECORD_SECONDS;
+
+    let data_len = record_to_file(volume_mgr, file, &mut i2s, max_data_bytes, BLOCK_TIME)?;
+    patch_header_sizes(volume_mgr, file, data_len)?;
+
+    volume_mgr
+        .close_file(file)
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+
+    info!(
+        "========== Finished recording {:?} bytes to {:?} ==========",
+        data_len, RECORD_FILE
+    );
+
+    Ok(())
+}
 
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -75,58 +209,13 @@ fn main() -> anyhow::Result<()> {
         &spi_config,
     )?;
 
-    //============================================================================================================
-    //                                  Create the I2S driver
-    // auto_clear = false so that the 2 dma buffers are a ring buffer
-    // dma_bufffer_count = 2 , minimum dma buffer count
-    // frames_per_buffer = 512 frames; is 1024 bytes for mono or 2048 bytes for stereo
-    // bits per sample = 16 bits = 2 bytes
-    // data bytes for a mono frame = 2 bytes
-    // data bytes for a stereo frame = 4 bytes
-    // total allocated storage for mono wav = 2048 bytes == 512 frames x 2 data bytes per mono frame x 2 dma buffers
-    //
-    // philips_slot_default =
-    //      number of slots = 2 slots (one left channel slot, one right channel slot),
-    //      sample size = 16 bits,
-    //      slotMode = Mono
-    //
-    // one frame = one lrclk cycle or ws cycle
-    // one frame for a 16 bits per sample = 32 bits; 16 bits for left channel and 16 bits per right channel = 4 bytes
-    // one frame for mono = transmit data on left channel, the right channel is 0 for all 16 bits
-    // one frame for stereo = transmit data on both channels
-    //
-    // For Philips Format
-    // mono = left channel (left slot) has data, right channel (right slot has all 0 for 16 bits)
-    // stereo = left channels (left slot) has data, right channel (right slot) has data
-    //
-    // With sample rate = 44.1KHz
-    // time to send one frame (one lrclk cycle) = 1 / 44.1KHz = 22.675 micro seconds
-    // time to send 512 frames = 512 x 22.675 microseconds = 11.6 milliseconds
-    //
-    // Note from the MAX98357A data sheet:
-    // LRCLK ONLY supports 8kHz, 16kHz, 32kHz, 44.1kHz, 48kHz, 88.2kHz and 96kHz frequencies.
-    // LRCLK clocks at 11.025kHz, 12kHz, 22.05kHz and 24kHz are NOT supported.
-    //
-    // So SAMPLE_RATE_HZ must be one of the following: 8kHz, 16kHz, 32kHz, 44.1kHz, 48kHz, 88.2kHz, 96kHz
-    //============================================================================================================
-    info!("========== Creating I2S driver ==========");
-    let i2s_config = StdConfig::new(
-        Config::default()
-            .auto_clear(false)
-            .dma_buffer_count(2)
-            .frames_per_buffer(512),
-        StdClkConfig::from_sample_rate_hz(SAMPLE_RATE_HZ),
-        StdSlotConfig::philips_slot_default(DataBitWidth::Bits16, SlotMode::Mono),
-        StdGpioConfig::default(),
-    );
-
+    // I2S peripheral and pins are kept around (not moved into a driver yet) so the driver can be
+    // rebuilt with a fresh clock/slot config whenever a track's sample rate, bit width or channel
+    // count differs from the previous one.
     let i2s_0 = peripherals.i2s0;
     let bclk = peripherals.pins.gpio0; // version 1.1, version 1.0 uses gpio19
     let dout = peripherals.pins.gpio17;
     let ws = peripherals.pins.gpio18; // same as lrclk
-    let mclk = AnyIOPin::none();
-
-    let mut i2s = I2sDriver::<I2sTx>::new_std_tx(i2s_0, &i2s_config, bclk, dout, mclk, ws)?;
 
     //============================================================================================================
     //                      Create the SD Card Interface using SPI device driver
@@ -150,103 +239,124 @@ fn main() -> anyhow::Result<()> {
         .open_root_dir(volume)
         .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
 
-    // Open the "WAV_FILE" located in the root directory
-    info!("========== Opening wav file ==========");
-    let wav_file = volume_mgr
-        .open_file_in_dir(root_dir, WAV_FILE, embedded_sdmmc::Mode::ReadOnly)
-        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+    if RECORD_MODE {
+        let din = peripherals.pins.gpio16; // I2S mic data out / ESP32S3 data in
+        record_new_wav_file(&mut volume_mgr, root_dir, i2s_0, bclk, din, ws)?;
+
+        info!("========== Goodbye ==========");
+        return Ok(());
+    }
 
     //============================================================================================================
-    //                              Read header from WAV file
+    //                      Scan the root directory for every .wav file and parse its header
     //============================================================================================================
-    info!("========== Reading header from WAV file ==========");
-    let mut header = [0u8; BYTES_IN_HEADER as usize];
+    info!("========== Scanning root directory for wav files ==========");
+    let mut wav_file_names: Vec<String> = Vec::new();
     volume_mgr
-        .read(wav_file, &mut header)
-        .expect("read header from wav file");
-    let riff_id = std::str::from_utf8(&header[0..4]).unwrap();
-    let file_size = u32::from_le_bytes(header[4..8].try_into().unwrap());
-    let file_type = std::str::from_utf8(&header[8..12]).unwrap();
-    let chunk_format = std::str::from_utf8(&header[12..16]).unwrap();
-    let size_of_format_section = u32::from_le_bytes(header[16..20].try_into().unwrap());
-    let format = u16::from_le_bytes(header[20..22].try_into().unwrap());
-    let num_of_channels = u16::from_le_bytes(header[22..24].try_into().unwrap());
-    let sampling_rate = u32::from_le_bytes(header[24..28].try_into().unwrap());
-    let byte_rate = u32::from_le_bytes(header[28..32].try_into().unwrap());
-    let block_align = u16::from_le_bytes(header[32..34].try_into().unwrap());
-    let bits_per_sample = u16::from_le_bytes(header[34..36].try_into().unwrap());
-    let data_section_id = std::str::from_utf8(&header[36..40]).unwrap();
-    let size_of_data = u32::from_le_bytes(header[40..44].try_into().unwrap());
-
-    warn!("========== Header Info ==========");
-    warn!("riff ID = {:?}", riff_id);
-    warn!("file size minus 8 bytes = {:?}", file_size);
-    warn!("RIFF format = {:?}", file_type);
-    warn!("chunk format ID = {:?}", chunk_format);
-    warn!("size of format section - 8 = {:?}", size_of_format_section);
-    warn!("format = {:?}", format);
-    warn!(
-        "number of channels (1=mono, 2=stereo) = {:?}",
-        num_of_channels
-    );
-    warn!("sampling rate = {:?}", sampling_rate);
-    warn!("byte rate = {:?}", byte_rate);
-    warn!("block align = {:?}", block_align);
-    warn!("bits per sample = {:?}", bits_per_sample);
-    warn!("data section id = {:?}", data_section_id);
-    warn!("data size in bytes = {:?}", size_of_data);
-
-    let mut fake_buffer = [0u8; 1024];
+        .iterate_dir(root_dir, |entry| {
+            if entry.attributes.is_directory() {
+                return;
+            }
+
+            let file_name = entry.name.to_string();
+            // 8.3 FAT names come back uppercase, so compare case-insensitively.
+            if file_name.to_uppercase().ends_with(".WAV") {
+                wav_file_names.push(file_name);
+            }
+        })
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
 
-    // Set file index to the end of the header section
-    volume_mgr
-        .file_seek_from_start(wav_file, BYTES_IN_HEADER as u32)
-        .expect("failed to seek");
+    let mut playlist: Vec<WavEntry> = Vec::new();
+    for file_name in wav_file_names {
+        let uri = format!("file://{}", file_name);
+        let info = {
+            let mut source = open_audio_source(&uri, &mut volume_mgr, root_dir)?;
+            parse_wav_header(&mut *source)
+                .map_err(|e| anyhow::anyhow!("failed to parse {} header: {:?}", file_name, e))?
+        };
+
+        info!("Found {}: {:?} Hz", file_name, info.format.sample_rate);
+        playlist.push(WavEntry { file_name, info });
+    }
 
-    let mut now = Instant::now();
-    let _bytes_read = volume_mgr.read(wav_file, &mut fake_buffer).expect("read");
-    let new_now = Instant::now();
-    info!(
-        "========== Time to read 1024 bytes {:?} ==========",
-        new_now.duration_since(now)
-    );
+    if playlist.is_empty() {
+        warn!("========== No wav files found on SD card ==========");
+    }
 
     //============================================================================================================
-    //                                      Play WAV file
+    //                                      Play every wav file in the playlist
     //============================================================================================================
-    info!("========== Started playing {:?} file ==========", WAV_FILE);
+    let mut i2s: Option<I2sDriver<I2sTx>> = None;
+    let mut current_format: Option<AudioFormat> = None;
+
+    for entry in &playlist {
+        if current_format != Some(entry.info.format) {
+            info!("========== (Re)configuring I2S driver for {} ==========", entry.file_name);
+            let i2s_config = build_i2s_config(&entry.info.format)?;
+
+            // Drop the previous driver (if any) before cloning its pins/peripheral below, so the
+            // hardware is actually released and not still claimed by two live I2sDriver instances.
+            i2s = None;
+
+            // SAFETY: the previous driver has just been dropped above, so the peripheral and pins
+            // it was using are free to be claimed again.
+            let i2s_0 = unsafe { i2s_0.clone_unchecked() };
+            let bclk = unsafe { bclk.clone_unchecked() };
+            let dout = unsafe { dout.clone_unchecked() };
+            let ws = unsafe { ws.clone_unchecked() };
+
+            i2s = Some(I2sDriver::<I2sTx>::new_std_tx(
+                i2s_0,
+                &i2s_config,
+                bclk,
+                dout,
+                AnyIOPin::none(),
+                ws,
+            )?);
+            current_format = Some(entry.info.format);
+        }
 
-    // CHUNK_SIZE = 1024 bytes == 512 frames where the left slot contains data from buffer (2 bytes),
-    // and the right slot (2 bytes) is automatically set to zero because wav is mono
-    const CHUNK_SIZE: usize = 1024;
-    let mut buffer = [0u8; CHUNK_SIZE];
-    let mut bytes_read: usize;
-    let mut data_read: usize = 0;
+        info!("========== Started playing {:?} file ==========", entry.file_name);
 
-    // Reset the file index to the end of header section
-    volume_mgr
-        .file_seek_from_start(wav_file, BYTES_IN_HEADER as u32)
-        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+        let uri = format!("file://{}", entry.file_name);
+        let mut source = open_audio_source(&uri, &mut volume_mgr, root_dir)?;
+        source.seek(entry.info.data_offset)?;
 
-    now = Instant::now();
-    i2s.tx_enable().unwrap();
+        let driver = i2s.as_mut().expect("i2s driver was just (re)configured");
 
-    while data_read < (size_of_data) as usize {
-        bytes_read = volume_mgr
-            .read(wav_file, &mut buffer)
-            .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
-        data_read += bytes_read;
+        // Prime the (non-auto-clearing) DMA ring with silence so the first frames out are
+        // zeros rather than whatever was left over from the last track, then enable tx.
+        let dma_ring_bytes =
+            FRAMES_PER_BUFFER * entry.info.format.block_align() as usize * DMA_BUFFER_COUNT as usize;
+        prime_silence(driver, dma_ring_bytes, BLOCK_TIME)?;
 
-        i2s.write_all(&buffer[..bytes_read], BLOCK_TIME.into())
-            .map_err(|e| anyhow::anyhow!("I2S error: {:?}", e))?;
-    }
+        let now = Instant::now();
+        driver.tx_enable().unwrap();
 
-    i2s.tx_disable().unwrap();
+        let fade_in_samples =
+            entry.info.format.sample_rate * entry.info.format.num_channels as u32 * FADE_IN_MS
+                / 1000;
+        let mut gain = GainProcessor::new(
+            PLAYBACK_GAIN,
+            fade_in_samples,
+            entry.info.format.bits_per_sample,
+        );
 
-    info!(
-        "========== Finsihed playing WAV file, took {:?} seconds to play ==========",
-        Instant::now().duration_since(now)
-    );
+        play_prefetched(&mut *source, entry.info.data_len, driver, BLOCK_TIME, &mut gain)?;
+
+        driver.tx_disable().unwrap();
+
+        info!(
+            "========== Finished playing {}, took {:?} ==========",
+            entry.file_name,
+            Instant::now().duration_since(now)
+        );
+
+        // Dropping `source` here (rather than at the end of the loop body, where it would drop
+        // anyway) makes explicit that the SD card's lock assertions require the file handle to be
+        // closed before the same (or another) file can be reopened on the next iteration.
+        drop(source);
+    }
 
     FreeRtos::delay_ms(5000);
 
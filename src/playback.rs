@@ -0,0 +1,111 @@
+use std::{
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+};
+
+use esp_idf_hal::{
+    delay::TickType,
+    i2s::{I2sDriver, I2sTx},
+};
+
+use crate::{audio_source::AudioSource, gain::GainProcessor};
+
+/// Size, in bytes, of each prefetch buffer. Larger buffers absorb longer SD
+/// read latency spikes at the cost of more RAM and a bit more startup/end latency.
+pub const PREFETCH_BUFFER_SIZE: usize = 4096;
+
+/// Number of prefetch buffers in flight between the reader task and the I2S
+/// feed. 2 is a plain double buffer; raise it for extra headroom on slower cards.
+pub const PREFETCH_BUFFER_COUNT: usize = 3;
+
+/// Plays `data_len` bytes of PCM read from `source`, feeding `i2s` from a
+/// dedicated reader task instead of reading and writing serially on one
+/// thread. `source` is read sequentially from whatever position it's
+/// already positioned at, so it works the same whether the bytes are
+/// coming off the SD card or streaming in over HTTP.
+///
+/// A bounded channel hands filled buffers from the reader task to this
+/// thread, and a second channel hands emptied buffers back for refilling --
+/// so one buffer can be read from `source` while another is being DMA'd
+/// out over I2S, and a read latency spike no longer stalls the I2S feed directly.
+pub fn play_prefetched(
+    source: &mut dyn AudioSource,
+    data_len: u32,
+    i2s: &mut I2sDriver<I2sTx>,
+    block_time: TickType,
+    gain: &mut GainProcessor,
+) -> anyhow::Result<()> {
+    let (filled_tx, filled_rx): (SyncSender<Vec<u8>>, Receiver<Vec<u8>>) =
+        sync_channel(PREFETCH_BUFFER_COUNT);
+    let (empty_tx, empty_rx): (SyncSender<Vec<u8>>, Receiver<Vec<u8>>) =
+        sync_channel(PREFETCH_BUFFER_COUNT);
+
+    for _ in 0..PREFETCH_BUFFER_COUNT {
+        empty_tx
+            .send(vec![0u8; PREFETCH_BUFFER_SIZE])
+            .map_err(|_| anyhow::anyhow!("prefetch channel closed"))?;
+    }
+
+    thread::scope(|scope| -> anyhow::Result<()> {
+        let reader = scope.spawn(|| -> anyhow::Result<()> {
+            let mut bytes_read_total: u32 = 0;
+
+            while bytes_read_total < data_len {
+                let Ok(mut buffer) = empty_rx.recv() else {
+                    break;
+                };
+
+                let to_read =
+                    (data_len - bytes_read_total).min(PREFETCH_BUFFER_SIZE as u32) as usize;
+                let bytes_read = source.read(&mut buffer[..to_read])?;
+                if bytes_read == 0 {
+                    return Err(anyhow::anyhow!(
+                        "audio source ended after {} of {} expected bytes",
+                        bytes_read_total,
+                        data_len
+                    ));
+                }
+                buffer.truncate(bytes_read);
+                bytes_read_total += bytes_read as u32;
+
+                if filled_tx.send(buffer).is_err() {
+                    break;
+                }
+            }
+
+            Ok(())
+        });
+
+        for mut buffer in filled_rx.iter() {
+            gain.process(&mut buffer);
+
+            i2s.write_all(&buffer, block_time.into())
+                .map_err(|e| anyhow::anyhow!("I2S error: {:?}", e))?;
+
+            buffer.resize(PREFETCH_BUFFER_SIZE, 0);
+            if empty_tx.send(buffer).is_err() {
+                break;
+            }
+        }
+
+        reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("prefetch reader task panicked"))??;
+
+        Ok(())
+    })
+}
+
+/// Writes `num_bytes` of silence into the I2S DMA ring before `tx_enable` is
+/// called. The ring buffer is not auto-clearing (`auto_clear(false)`), so
+/// without this the first frames played out would be whatever stale data was
+/// already sitting in the DMA buffers rather than silence.
+pub fn prime_silence(
+    i2s: &mut I2sDriver<I2sTx>,
+    num_bytes: usize,
+    block_time: TickType,
+) -> anyhow::Result<()> {
+    let silence = vec![0u8; num_bytes];
+    i2s.write_all(&silence, block_time.into())
+        .map_err(|e| anyhow::anyhow!("I2S error: {:?}", e))
+}
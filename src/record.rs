@@ -0,0 +1,131 @@
+use embedded_sdmmc::{BlockDevice, RawFile, TimeSource, VolumeManager};
+use esp_idf_hal::{delay::TickType, i2s::I2sRx, i2s::I2sDriver};
+
+use crate::wav::AudioFormat;
+
+const FMT_CHUNK_SIZE: u32 = 16;
+const PCM_FORMAT_TAG: u16 = 1;
+
+/// Writes a canonical 44-byte WAV header to a freshly created file, with the
+/// `RIFF` chunk size and `data` chunk size left as placeholders (zero) since
+/// the final byte counts aren't known until recording finishes.
+pub fn write_placeholder_header<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: RawFile,
+    format: &AudioFormat,
+) -> anyhow::Result<()>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let block_align = format.block_align();
+    let byte_rate = format.byte_rate();
+
+    let mut header = [0u8; 44];
+    header[0..4].copy_from_slice(b"RIFF");
+    // header[4..8] (RIFF chunk size) patched in by `patch_header_sizes` once known.
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&FMT_CHUNK_SIZE.to_le_bytes());
+    header[20..22].copy_from_slice(&PCM_FORMAT_TAG.to_le_bytes());
+    header[22..24].copy_from_slice(&format.num_channels.to_le_bytes());
+    header[24..28].copy_from_slice(&format.sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&format.bits_per_sample.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    // header[40..44] (data chunk size) patched in by `patch_header_sizes` once known.
+
+    volume_mgr
+        .write(file, &header)
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+
+    Ok(())
+}
+
+/// Seeks back to the start of `file` and patches the `RIFF` chunk size and
+/// `data` chunk size fields now that the final recorded byte count is known.
+pub fn patch_header_sizes<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: RawFile,
+    data_len: u32,
+) -> anyhow::Result<()>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    let riff_chunk_size = 36 + data_len; // everything after the RIFF id + size field
+
+    volume_mgr
+        .file_seek_from_start(file, 4)
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+    volume_mgr
+        .write(file, &riff_chunk_size.to_le_bytes())
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+
+    volume_mgr
+        .file_seek_from_start(file, 40)
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+    volume_mgr
+        .write(file, &data_len.to_le_bytes())
+        .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+
+    Ok(())
+}
+
+/// Records PCM frames from an I2S microphone into `file`, starting right
+/// after the placeholder header, until `max_data_bytes` have been written.
+/// Returns the number of PCM bytes actually recorded.
+pub fn record_to_file<
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+>(
+    volume_mgr: &mut VolumeManager<D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+    file: RawFile,
+    i2s: &mut I2sDriver<I2sRx>,
+    max_data_bytes: u32,
+    block_time: TickType,
+) -> anyhow::Result<u32>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    const CHUNK_SIZE: usize = 1024;
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut data_written: u32 = 0;
+
+    i2s.rx_enable()
+        .map_err(|e| anyhow::anyhow!("I2S error: {:?}", e))?;
+
+    while data_written < max_data_bytes {
+        let to_read = (max_data_bytes - data_written).min(CHUNK_SIZE as u32) as usize;
+        let bytes_read = i2s
+            .read(&mut buffer[..to_read], block_time.into())
+            .map_err(|e| anyhow::anyhow!("I2S error: {:?}", e))?;
+
+        volume_mgr
+            .write(file, &buffer[..bytes_read])
+            .map_err(|e| anyhow::anyhow!("SdCard error: {:?}", e))?;
+        data_written += bytes_read as u32;
+    }
+
+    i2s.rx_disable()
+        .map_err(|e| anyhow::anyhow!("I2S error: {:?}", e))?;
+
+    Ok(data_written)
+}
@@ -0,0 +1,280 @@
+use std::convert::TryInto;
+
+use crate::audio_source::AudioSource;
+
+/// Sample rate, channel count and bit depth needed to configure the I2S
+/// driver. Shared between the playback path (where it's parsed from a file's
+/// `fmt ` chunk) and the recording path (where it's picked up front and
+/// written into the file it creates), so a file this device records also
+/// plays back correctly on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub num_channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+}
+
+impl AudioFormat {
+    /// Bytes occupied by one frame (one sample per channel).
+    pub fn block_align(&self) -> u16 {
+        self.num_channels * (self.bits_per_sample / 8)
+    }
+
+    /// Bytes of PCM data per second.
+    pub fn byte_rate(&self) -> u32 {
+        self.sample_rate * self.block_align() as u32
+    }
+}
+
+/// A WAV file's [`AudioFormat`] plus the location of its `data` chunk, as
+/// discovered by [`parse_wav_header`]. `data_offset` is the byte offset of
+/// the `data` chunk's payload from the start of the stream; meaningful for
+/// seeking back to it on a seekable [`AudioSource`], meaningless (and
+/// unused) on a streaming one.
+pub struct WavInfo {
+    pub format: AudioFormat,
+    pub data_offset: u32,
+    pub data_len: u32,
+}
+
+/// Walks the RIFF subchunks of `source` from its current position and
+/// returns the WAV file's [`WavInfo`].
+///
+/// Real-world WAV files are not always the canonical 44-byte header followed
+/// immediately by `data`: `LIST`/`INFO`/`fact` chunks and padded `fmt `
+/// sections are common. Rather than assuming a fixed layout, this walks each
+/// chunk's 4-byte ID + 4-byte little-endian size, captures the fields we care
+/// about from `fmt `, skips anything else (honoring the RIFF rule that
+/// odd-sized chunks are padded with a trailing null byte), and stops as soon
+/// as `data` is found. Chunks are skipped by reading and discarding their
+/// bytes rather than seeking, so this works the same over a non-seekable
+/// source such as an HTTP stream.
+pub fn parse_wav_header(source: &mut dyn AudioSource) -> anyhow::Result<WavInfo> {
+    let mut riff_header = [0u8; 12];
+    read_exact(source, &mut riff_header)?;
+
+    let riff_id = std::str::from_utf8(&riff_header[0..4]).unwrap_or_default();
+    let wave_id = std::str::from_utf8(&riff_header[8..12]).unwrap_or_default();
+    if riff_id != "RIFF" || wave_id != "WAVE" {
+        return Err(anyhow::anyhow!(
+            "not a WAV file (riff id = {:?}, format = {:?})",
+            riff_id,
+            wave_id
+        ));
+    }
+
+    let mut offset: u32 = 12;
+    let mut num_channels: Option<u16> = None;
+    let mut sample_rate: Option<u32> = None;
+    let mut bits_per_sample: Option<u16> = None;
+
+    loop {
+        let mut chunk_header = [0u8; 8];
+        read_exact(source, &mut chunk_header)?;
+
+        let chunk_id = std::str::from_utf8(&chunk_header[0..4])
+            .unwrap_or_default()
+            .to_owned();
+        let chunk_size = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap());
+        let chunk_data_offset = offset + 8;
+        // RIFF pads odd-sized chunks with a null byte so the next chunk id starts on an even offset.
+        let padded_chunk_size = chunk_size + (chunk_size & 1);
+
+        match chunk_id.as_str() {
+            "fmt " => {
+                if chunk_size < 16 {
+                    return Err(anyhow::anyhow!(
+                        "fmt chunk too short: {} bytes (need at least 16)",
+                        chunk_size
+                    ));
+                }
+
+                let mut fmt_chunk = [0u8; 16];
+                read_exact(source, &mut fmt_chunk)?;
+
+                num_channels = Some(u16::from_le_bytes(fmt_chunk[2..4].try_into().unwrap()));
+                sample_rate = Some(u32::from_le_bytes(fmt_chunk[4..8].try_into().unwrap()));
+                bits_per_sample = Some(u16::from_le_bytes(fmt_chunk[14..16].try_into().unwrap()));
+
+                // WAVE_FORMAT_EXTENSIBLE-style `fmt ` chunks carry extra bytes past the 16 we read.
+                skip(source, padded_chunk_size - 16)?;
+            }
+            "data" => {
+                let format = AudioFormat {
+                    num_channels: num_channels
+                        .ok_or_else(|| anyhow::anyhow!("data chunk found before fmt chunk"))?,
+                    sample_rate: sample_rate
+                        .ok_or_else(|| anyhow::anyhow!("data chunk found before fmt chunk"))?,
+                    bits_per_sample: bits_per_sample
+                        .ok_or_else(|| anyhow::anyhow!("data chunk found before fmt chunk"))?,
+                };
+
+                return Ok(WavInfo {
+                    format,
+                    data_offset: chunk_data_offset,
+                    data_len: chunk_size,
+                });
+            }
+            _ => {
+                // Unknown chunk (LIST, fact, INFO, ...): skip its payload rather than assume it's absent.
+                skip(source, padded_chunk_size)?;
+            }
+        }
+
+        offset = chunk_data_offset + padded_chunk_size;
+    }
+}
+
+/// Fills `buf` completely from `source`, treating a short read as an error
+/// since a WAV header that runs out of bytes mid-field is truncated/corrupt.
+fn read_exact(source: &mut dyn AudioSource, buf: &mut [u8]) -> anyhow::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let bytes_read = source.read(&mut buf[filled..])?;
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("unexpected end of stream while reading WAV header"));
+        }
+        filled += bytes_read;
+    }
+    Ok(())
+}
+
+/// Discards `num_bytes` from `source` by reading them into a scratch buffer.
+fn skip(source: &mut dyn AudioSource, num_bytes: u32) -> anyhow::Result<()> {
+    let mut scratch = [0u8; 64];
+    let mut remaining = num_bytes as usize;
+    while remaining > 0 {
+        let to_read = remaining.min(scratch.len());
+        let bytes_read = source.read(&mut scratch[..to_read])?;
+        if bytes_read == 0 {
+            return Err(anyhow::anyhow!("unexpected end of stream while skipping WAV chunk"));
+        }
+        remaining -= bytes_read;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An [`AudioSource`] over an in-memory byte slice, so the chunk-walking
+    /// logic can be exercised on the host without an SD card or network.
+    struct SliceSource<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> SliceSource<'a> {
+        fn new(data: &'a [u8]) -> Self {
+            Self { data, pos: 0 }
+        }
+    }
+
+    impl<'a> AudioSource for SliceSource<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+            let remaining = &self.data[self.pos..];
+            let n = remaining.len().min(buf.len());
+            buf[..n].copy_from_slice(&remaining[..n]);
+            self.pos += n;
+            Ok(n)
+        }
+
+        fn seek(&mut self, offset: u32) -> anyhow::Result<()> {
+            self.pos = offset as usize;
+            Ok(())
+        }
+    }
+
+    fn chunk(id: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(id);
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn fmt_payload(num_channels: u16, sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+        let block_align = num_channels * (bits_per_sample / 8);
+        let byte_rate = sample_rate * block_align as u32;
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&1u16.to_le_bytes()); // PCM format tag
+        out.extend_from_slice(&num_channels.to_le_bytes());
+        out.extend_from_slice(&sample_rate.to_le_bytes());
+        out.extend_from_slice(&byte_rate.to_le_bytes());
+        out.extend_from_slice(&block_align.to_le_bytes());
+        out.extend_from_slice(&bits_per_sample.to_le_bytes());
+        out
+    }
+
+    fn riff_wave(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.iter().flatten().copied().collect();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(4 + body.len() as u32).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    #[test]
+    fn parses_canonical_header() {
+        let data = riff_wave(&[
+            chunk(b"fmt ", &fmt_payload(1, 44_100, 16)),
+            chunk(b"data", &[1, 2, 3, 4]),
+        ]);
+
+        let info = parse_wav_header(&mut SliceSource::new(&data)).unwrap();
+
+        assert_eq!(info.format.num_channels, 1);
+        assert_eq!(info.format.sample_rate, 44_100);
+        assert_eq!(info.format.bits_per_sample, 16);
+        assert_eq!(info.data_len, 4);
+    }
+
+    #[test]
+    fn skips_unknown_chunks_with_odd_size_padding() {
+        let data = riff_wave(&[
+            chunk(b"fmt ", &fmt_payload(2, 48_000, 16)),
+            chunk(b"LIST", b"abc"), // odd-sized payload, so it's null-padded
+            chunk(b"data", &[9, 9]),
+        ]);
+
+        let info = parse_wav_header(&mut SliceSource::new(&data)).unwrap();
+
+        assert_eq!(info.format.sample_rate, 48_000);
+        assert_eq!(info.data_len, 2);
+    }
+
+    #[test]
+    fn skips_oversized_extensible_fmt_chunk() {
+        let mut fmt = fmt_payload(2, 44_100, 24);
+        fmt.extend_from_slice(&[0u8; 10]); // WAVE_FORMAT_EXTENSIBLE tail past the 16 bytes we read
+        let data = riff_wave(&[chunk(b"fmt ", &fmt), chunk(b"data", &[0; 8])]);
+
+        let info = parse_wav_header(&mut SliceSource::new(&data)).unwrap();
+
+        assert_eq!(info.format.bits_per_sample, 24);
+        assert_eq!(info.data_len, 8);
+    }
+
+    #[test]
+    fn rejects_truncated_fmt_chunk() {
+        let data = riff_wave(&[chunk(b"fmt ", &[0u8; 8])]);
+
+        assert!(parse_wav_header(&mut SliceSource::new(&data)).is_err());
+    }
+
+    #[test]
+    fn rejects_non_wav_header() {
+        let mut data = riff_wave(&[chunk(b"fmt ", &fmt_payload(1, 8_000, 16))]);
+        data[8..12].copy_from_slice(b"JUNK");
+
+        assert!(parse_wav_header(&mut SliceSource::new(&data)).is_err());
+    }
+}